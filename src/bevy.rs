@@ -0,0 +1,62 @@
+//! Bevy asset pipeline integration, gated behind the `bevy` cargo feature so
+//! the core crate stays dependency-free.
+//!
+//! With the feature enabled a `.toy` file can be loaded through the asset
+//! server and referenced by `Handle<ToyAsset>`:
+//!
+//! ```ignore
+//! let handle: Handle<ToyAsset> = asset_server.load("scene.toy");
+//! ```
+
+use crate::types::Project;
+use crate::ToyResult;
+
+use bevy::asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext};
+use bevy::reflect::TypePath;
+use bevy::asset::Asset;
+
+/// A loaded `.toy` buffer living in the Bevy asset system. Owns the backing
+/// bytes so it can satisfy `Asset`'s `'static` bound; call [`ToyAsset::project`]
+/// to borrow a [`Project`] view over them.
+#[derive(Asset, TypePath, Debug, Clone)]
+pub struct ToyAsset {
+	bytes: Vec<u8>,
+}
+
+impl ToyAsset {
+	/// Open a borrowed [`Project`] over the stored buffer. Meshes are parsed
+	/// lazily, matching [`Project::open`].
+	pub fn project(&self) -> ToyResult<Project<'_>> {
+		Project::open(&self.bytes)
+	}
+}
+
+/// Loads `.toy` files by deferring to the crate's own [`crate::load`].
+#[derive(Default)]
+pub struct ToyAssetLoader;
+
+impl AssetLoader for ToyAssetLoader {
+	type Asset = ToyAsset;
+	type Settings = ();
+	type Error = std::io::Error;
+
+	async fn load(
+		&self,
+		reader: &mut dyn Reader,
+		_settings: &Self::Settings,
+		_load_context: &mut LoadContext<'_>,
+	) -> Result<Self::Asset, Self::Error> {
+		let mut bytes = Vec::new();
+		reader.read_to_end(&mut bytes).await?;
+
+		// Validate eagerly so a malformed file fails at asset-load time.
+		crate::load(&bytes)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+
+		Ok(ToyAsset { bytes })
+	}
+
+	fn extensions(&self) -> &[&str] {
+		&["toy"]
+	}
+}
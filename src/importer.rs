@@ -5,45 +5,70 @@ use std::convert::TryInto;
 use common::*;
 use failure::{ensure, bail, format_err};
 
-const SCENE_VERSION: u8 = 2;
+const SCENE_VERSION: u8 = 3;
 
-pub fn load(data: &[u8]) -> ToyResult<Project> {
-	let reader = ToyReader { buf: data };
-	reader.read_all()
+pub fn load(data: &[u8]) -> ToyResult<Project<'_>> {
+	Project::load(data)
 }
 
+pub fn save(project: &Project) -> ToyResult<Vec<u8>> {
+	project.load_all()?;
 
-type Tag = [u8; 4];
+	let mut writer = ToyWriter::new();
+	writer.write_all(project);
+	Ok(writer.buf)
+}
 
-struct ToyReader<'data> { buf: &'data [u8] }
+/// Index a `.toy` buffer in a single pass: scenes and entities are parsed
+/// eagerly, but each `MESH` section only has its byte range recorded so it can
+/// be parsed lazily on first access. Backs [`Project::open`].
+pub(crate) fn open(data: &[u8]) -> ToyResult<Project<'_>> {
+	let mut reader = ToyReader { buf: data };
+	reader.read_magic()?;
+
+	let mut entities = Vec::new();
+	let mut scenes = Vec::new();
+	let mut mesh_ranges = Vec::new();
+
+	while !reader.buf.is_empty() {
+		let tag = reader.read_tag()?;
+		let section_size = reader.read_u32()? as usize;
+		ensure!(section_size <= reader.buf.len(), "Invalid section size for '{}'", tag_to_string(&tag));
+
+		let start = data.len() - reader.buf.len();
+		let (body, rest) = reader.buf.split_at(section_size);
+		reader.buf = rest;
+
+		let to_err = |e| format_err!("While parsing '{}' section: {}", tag_to_string(&tag), e);
+
+		match &tag {
+			b"SCNE" => scenes.push(ToyReader{ buf: body }.read_scene().map_err(to_err)?),
+			b"ENTY" => entities.push(ToyReader{ buf: body }.read_entity().map_err(to_err)?),
+			b"MESH" => mesh_ranges.push(start .. start + section_size),
+			_ => bail!("Unexpected tag '{}' encountered", tag_to_string(&tag))
+		}
+	}
 
-impl<'d> ToyReader<'d> {
-	fn read_all(mut self) -> ToyResult<Project> {
-		self.read_magic()?;
+	Ok(Project {
+		scenes,
+		entities,
+		meshes: MeshStore::new(data, mesh_ranges),
+	})
+}
 
-		let mut meshes = Vec::new();
-		let mut entities = Vec::new();
-		let mut scenes = Vec::new();
+/// Parse a single `MESH` section body into a [`Mesh`]. Called by
+/// [`MeshStore`] the first time a mesh id is accessed.
+pub(crate) fn parse_mesh(body: &[u8]) -> ToyResult<Mesh> {
+	let mut reader = ToyReader { buf: body };
+	reader.read_mesh()
+}
 
-		while !self.buf.is_empty() {
-			let (tag, mut section) = self.read_section()?;
 
-			let to_err = |e| format_err!("While parsing '{}' section: {}", tag_to_string(&tag), e);
+type Tag = [u8; 4];
 
-			match &tag {
-				b"SCNE" => scenes.push(section.read_scene().map_err(to_err)?),
-				b"MESH" => meshes.push(section.read_mesh().map_err(to_err)?),
-				b"ENTY" => entities.push(section.read_entity().map_err(to_err)?),
-				_ => bail!("Unexpected tag '{}' encountered", tag_to_string(&tag))
-			}
-		}
+struct ToyReader<'data> { buf: &'data [u8] }
 
-		Ok(Project {
-			scenes,
-			entities,
-			meshes,
-		})
-	}
+impl<'d> ToyReader<'d> {
 
 	fn read_magic(&mut self) -> ToyResult<()> {
 		ensure!(&self.buf[..3] == b"TOY", "Expected magic string");
@@ -55,72 +80,132 @@ impl<'d> ToyReader<'d> {
 		Ok(())
 	}
 
-	fn read_section(&mut self) -> ToyResult<(Tag, ToyReader<'_>)> {
-		let tag = self.read_tag()?;
-		let section_size = self.read_u32()? as usize;
-		ensure!(section_size <= self.buf.len(), "Invalid section size for '{}'", tag_to_string(&tag));
-
-		let (section, rest) = self.buf.split_at(section_size);
-		self.buf = rest;
-
-		Ok((tag, ToyReader{ buf: section }))
-	}
-
-	fn read_mesh(&mut self) -> ToyResult<MeshData> {
+	fn read_mesh(&mut self) -> ToyResult<Mesh> {
 		let num_vertices = self.read_u16()? as usize;
-		let mut vertices = Vec::with_capacity(num_vertices);
+		let mut positions = Vec::with_capacity(num_vertices);
 		for _ in 0..num_vertices {
-			vertices.push(self.read_vec3()?);
+			positions.push(self.read_vec3()?);
 		}
 
 		let wide_indices = num_vertices >= 256;
 
 		let num_triangles = self.read_u16()? as usize;
 		let num_indices = num_triangles * 3;
-		let indices;
+		let mut indices = Vec::with_capacity(num_indices);
 
 		if wide_indices {
-			let mut indices_buf = Vec::with_capacity(num_indices);
 			for _ in 0..num_indices {
-				indices_buf.push(self.read_u16()?);
+				indices.push(self.read_u16()?);
 			}
-			indices = MeshIndices::U16(indices_buf);
-
 		} else {
-			let mut indices_buf = Vec::with_capacity(num_indices);
 			for _ in 0..num_indices {
-				indices_buf.push(self.read_u8()?);
+				indices.push(self.read_u8()? as u16);
 			}
-			indices = MeshIndices::U8(indices_buf);
 		}
 
 		let num_color_layers = self.read_u8()? as usize;
-		let mut color_data = Vec::with_capacity(num_color_layers);
+		let mut color_layers = Vec::with_capacity(num_color_layers);
 		for _ in 0..num_color_layers {
 			self.expect_tag(b"MDTA")?;
 
-			let layer_name = self.read_string()?;
+			let name = self.read_string()?;
+			let num_points = self.read_u16()? as usize;
+			ensure!(num_points == num_vertices, "Color layer '{}' different size to vertex list", name);
+
+			let mut data = Vec::with_capacity(num_points);
+			for _ in 0..num_points {
+				data.push(self.read_vec4()?);
+			}
+
+			color_layers.push(MeshColorLayer { name, data });
+		}
+
+		let num_uv_layers = self.read_u8()? as usize;
+		let mut uv_layers = Vec::with_capacity(num_uv_layers);
+		for _ in 0..num_uv_layers {
+			self.expect_tag(b"MUVT")?;
+
+			let name = self.read_string()?;
 			let num_points = self.read_u16()? as usize;
-			ensure!(num_points == num_vertices, "Color layer '{}' different size to vertex list", layer_name);
+			ensure!(num_points == num_vertices, "UV layer '{}' different size to vertex list", name);
 
-			let mut layer_data = Vec::with_capacity(num_points);
+			let mut data = Vec::with_capacity(num_points);
 			for _ in 0..num_points {
-				layer_data.push(self.read_vec4()?);
+				data.push(self.read_vec2()?);
 			}
 
-			color_data.push(MeshColorData {
-				name: layer_name,
-				data: layer_data,
-			})
+			uv_layers.push(MeshUvLayer { name, data });
 		}
 
-		Ok(MeshData {
-			positions: vertices,
+		let animation_data = if self.read_u8()? != 0 {
+			Some(self.read_animation_data(num_vertices)?)
+		} else {
+			None
+		};
+
+		Ok(Mesh {
+			positions,
 			indices,
-			color_data
+			color_layers,
+			uv_layers,
+			animation_data,
 		})
 	}
 
+	fn read_animation_data(&mut self, num_vertices: usize) -> ToyResult<MeshAnimationData> {
+		let num_bones = self.read_u16()? as usize;
+		let mut bones = Vec::with_capacity(num_bones);
+		for _ in 0..num_bones {
+			bones.push(MeshBone {
+				name: self.read_string()?,
+				head: self.read_vec3()?,
+				tail: self.read_vec3()?,
+			});
+		}
+
+		let num_weights = self.read_u16()? as usize;
+		ensure!(num_weights == num_vertices, "Weight table different size to vertex list");
+		let mut weights = Vec::with_capacity(num_weights);
+		for _ in 0..num_weights {
+			weights.push(MeshWeightVertex {
+				indices: [self.read_u8()?, self.read_u8()?, self.read_u8()?],
+				weights: [self.read_f32()?, self.read_f32()?, self.read_f32()?],
+			});
+		}
+
+		let num_animations = self.read_u16()? as usize;
+		let mut animations = Vec::with_capacity(num_animations);
+		for _ in 0..num_animations {
+			let name = self.read_string()?;
+			let fps = self.read_f32()?;
+
+			let num_channels = self.read_u16()? as usize;
+			let mut channels = Vec::with_capacity(num_channels);
+			for _ in 0..num_channels {
+				let bone_name = self.read_string()?;
+				let bone = bones.iter()
+					.position(|b| b.name == bone_name)
+					.ok_or_else(|| format_err!("Animation '{}' references unknown bone '{}'", name, bone_name))?;
+
+				let num_frames = self.read_u16()? as usize;
+				let mut frames = Vec::with_capacity(num_frames);
+				for _ in 0..num_frames {
+					frames.push(MeshAnimationFrame {
+						position: self.read_vec3()?,
+						rotation: self.read_quat()?,
+						scale: self.read_vec3()?,
+					});
+				}
+
+				channels.push(MeshAnimationChannel { bone, frames });
+			}
+
+			animations.push(MeshAnimation { name, fps, channels });
+		}
+
+		Ok(MeshAnimationData { bones, weights, animations })
+	}
+
 	fn read_entity(&mut self) -> ToyResult<EntityData> {
 		Ok(EntityData {
 			name: self.read_string()?,
@@ -184,6 +269,13 @@ impl<'d> ToyReader<'d> {
 		Ok(f32::from_bits(self.read_u32()?))
 	}
 
+	fn read_vec2(&mut self) -> ToyResult<Vec2> {
+		Ok(Vec2::new(
+			self.read_f32()?,
+			self.read_f32()?
+		))
+	}
+
 	fn read_vec3(&mut self) -> ToyResult<Vec3> {
 		Ok(Vec3::new(
 			self.read_f32()?,
@@ -228,4 +320,229 @@ fn tag_to_string(tag: &Tag) -> String {
 	unsafe {
 		std::str::from_utf8_unchecked(tag).into()
 	}
+}
+
+
+/// Anything that can be encoded into a `.toy` buffer. Mirrors the primitive
+/// readers on `ToyReader`: `byte_len` lets a section's size be computed up
+/// front, and `write_bytes` appends the little-endian encoding.
+trait Bytes {
+	fn byte_len(&self) -> usize;
+	fn write_bytes(&self, buf: &mut Vec<u8>);
+}
+
+impl Bytes for u8 {
+	fn byte_len(&self) -> usize { 1 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) { buf.push(*self); }
+}
+
+impl Bytes for u16 {
+	fn byte_len(&self) -> usize { 2 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_le_bytes()); }
+}
+
+impl Bytes for u32 {
+	fn byte_len(&self) -> usize { 4 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_le_bytes()); }
+}
+
+impl Bytes for f32 {
+	fn byte_len(&self) -> usize { 4 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) { buf.extend_from_slice(&self.to_bits().to_le_bytes()); }
+}
+
+impl Bytes for Vec2 {
+	fn byte_len(&self) -> usize { 2 * 4 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) {
+		self.x.write_bytes(buf);
+		self.y.write_bytes(buf);
+	}
+}
+
+impl Bytes for Vec3 {
+	fn byte_len(&self) -> usize { 3 * 4 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) {
+		self.x.write_bytes(buf);
+		self.y.write_bytes(buf);
+		self.z.write_bytes(buf);
+	}
+}
+
+impl Bytes for Vec4 {
+	fn byte_len(&self) -> usize { 4 * 4 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) {
+		self.x.write_bytes(buf);
+		self.y.write_bytes(buf);
+		self.z.write_bytes(buf);
+		self.w.write_bytes(buf);
+	}
+}
+
+impl Bytes for Quat {
+	fn byte_len(&self) -> usize { 4 * 4 }
+	fn write_bytes(&self, buf: &mut Vec<u8>) {
+		self.x.write_bytes(buf);
+		self.y.write_bytes(buf);
+		self.z.write_bytes(buf);
+		self.w.write_bytes(buf);
+	}
+}
+
+/// Length-prefixed, matching `ToyReader::read_string`: a `u8` byte count
+/// followed by the utf8 payload.
+impl Bytes for str {
+	fn byte_len(&self) -> usize { 1 + self.len() }
+	fn write_bytes(&self, buf: &mut Vec<u8>) {
+		(self.len() as u8).write_bytes(buf);
+		buf.extend_from_slice(self.as_bytes());
+	}
+}
+
+
+struct ToyWriter { buf: Vec<u8> }
+
+impl ToyWriter {
+	fn new() -> ToyWriter {
+		ToyWriter { buf: Vec::new() }
+	}
+
+	fn write_all(&mut self, project: &Project) {
+		self.write_magic();
+
+		for scene in project.scenes.iter() {
+			self.write_section(b"SCNE", |w| w.write_scene(scene));
+		}
+
+		for mesh in project.meshes.iter() {
+			self.write_section(b"MESH", |w| w.write_mesh(mesh));
+		}
+
+		for entity in project.entities.iter() {
+			self.write_section(b"ENTY", |w| w.write_entity(entity));
+		}
+	}
+
+	fn write_magic(&mut self) {
+		self.buf.extend_from_slice(b"TOY");
+		self.write(&SCENE_VERSION);
+	}
+
+	/// Emit a tagged section, back-patching the `u32` size field once the body
+	/// has been written.
+	fn write_section(&mut self, tag: &Tag, body: impl FnOnce(&mut ToyWriter)) {
+		self.buf.extend_from_slice(tag);
+
+		let size_pos = self.buf.len();
+		self.buf.extend_from_slice(&[0; 4]);
+
+		body(self);
+
+		let size = (self.buf.len() - size_pos - 4) as u32;
+		self.buf[size_pos..size_pos + 4].copy_from_slice(&size.to_le_bytes());
+	}
+
+	fn write_mesh(&mut self, mesh: &Mesh) {
+		let num_vertices = mesh.positions.len();
+		self.write(&(num_vertices as u16));
+		for position in mesh.positions.iter() {
+			self.write(position);
+		}
+
+		let wide_indices = num_vertices >= 256;
+
+		self.write(&((mesh.indices.len() / 3) as u16));
+		for &index in mesh.indices.iter() {
+			if wide_indices {
+				self.write(&index);
+			} else {
+				self.write(&(index as u8));
+			}
+		}
+
+		self.write(&(mesh.color_layers.len() as u8));
+		for layer in mesh.color_layers.iter() {
+			self.buf.extend_from_slice(b"MDTA");
+			self.write(&*layer.name);
+			self.write(&(layer.data.len() as u16));
+			for point in layer.data.iter() {
+				self.write(point);
+			}
+		}
+
+		self.write(&(mesh.uv_layers.len() as u8));
+		for layer in mesh.uv_layers.iter() {
+			self.buf.extend_from_slice(b"MUVT");
+			self.write(&*layer.name);
+			self.write(&(layer.data.len() as u16));
+			for point in layer.data.iter() {
+				self.write(point);
+			}
+		}
+
+		match &mesh.animation_data {
+			Some(animation_data) => {
+				self.write(&1u8);
+				self.write_animation_data(animation_data);
+			}
+
+			None => self.write(&0u8),
+		}
+	}
+
+	fn write_animation_data(&mut self, animation_data: &MeshAnimationData) {
+		self.write(&(animation_data.bones.len() as u16));
+		for bone in animation_data.bones.iter() {
+			self.write(&*bone.name);
+			self.write(&bone.head);
+			self.write(&bone.tail);
+		}
+
+		self.write(&(animation_data.weights.len() as u16));
+		for vertex in animation_data.weights.iter() {
+			for &index in vertex.indices.iter() {
+				self.write(&index);
+			}
+			for &weight in vertex.weights.iter() {
+				self.write(&weight);
+			}
+		}
+
+		self.write(&(animation_data.animations.len() as u16));
+		for animation in animation_data.animations.iter() {
+			self.write(&*animation.name);
+			self.write(&animation.fps);
+
+			self.write(&(animation.channels.len() as u16));
+			for channel in animation.channels.iter() {
+				self.write(&*animation_data.bones[channel.bone].name);
+
+				self.write(&(channel.frames.len() as u16));
+				for frame in channel.frames.iter() {
+					self.write(&frame.position);
+					self.write(&frame.rotation);
+					self.write(&frame.scale);
+				}
+			}
+		}
+	}
+
+	fn write_entity(&mut self, entity: &Entity) {
+		self.write(&*entity.name);
+		self.write(&entity.position);
+		self.write(&entity.rotation);
+		self.write(&entity.scale);
+		self.write(&entity.mesh_id);
+	}
+
+	fn write_scene(&mut self, scene: &Scene) {
+		self.write(&*scene.name);
+		self.write(&(scene.entities.len() as u32));
+		for &id in scene.entities.iter() {
+			self.write(&id);
+		}
+	}
+
+	fn write<B: Bytes + ?Sized>(&mut self, value: &B) {
+		value.write_bytes(&mut self.buf);
+	}
 }
\ No newline at end of file
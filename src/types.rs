@@ -1,11 +1,64 @@
+use crate::ToyResult;
 use common::*;
-use std::ops::Deref;
+use failure::format_err;
+use std::cell::OnceCell;
+use std::ops::{Deref, Range};
 
 #[derive(Debug, Clone)]
-pub struct Project {
+pub struct Project<'data> {
 	pub scenes: Vec<Scene>,
 	pub entities: Vec<Entity>,
-	pub meshes: Vec<Mesh>,
+	pub(crate) meshes: MeshStore<'data>,
+}
+
+/// Sparse, lazily-populated mesh store backing [`Project`]. Section byte ranges
+/// are recorded up front by `Project::open`; each `Mesh` is parsed from the
+/// borrowed buffer on first access and cached in place.
+#[derive(Debug, Clone)]
+pub(crate) struct MeshStore<'data> {
+	data: &'data [u8],
+	ranges: Vec<Range<usize>>,
+	cache: Vec<OnceCell<Mesh>>,
+}
+
+impl<'data> MeshStore<'data> {
+	pub(crate) fn new(data: &'data [u8], ranges: Vec<Range<usize>>) -> MeshStore<'data> {
+		let cache = ranges.iter().map(|_| OnceCell::new()).collect();
+		MeshStore { data, ranges, cache }
+	}
+
+	pub(crate) fn len(&self) -> usize {
+		self.ranges.len()
+	}
+
+	fn is_loaded(&self, id: usize) -> bool {
+		self.cache.get(id).is_some_and(|cell| cell.get().is_some())
+	}
+
+	fn mesh(&self, id: usize) -> ToyResult<&Mesh> {
+		let cell = self.cache.get(id)
+			.ok_or_else(|| format_err!("No mesh with id {}", id))?;
+
+		if let Some(mesh) = cell.get() {
+			return Ok(mesh)
+		}
+
+		let mesh = crate::importer::parse_mesh(&self.data[self.ranges[id].clone()])?;
+		Ok(cell.get_or_init(|| mesh))
+	}
+
+	fn load_all(&self) -> ToyResult<()> {
+		for id in 0..self.len() {
+			self.mesh(id)?;
+		}
+		Ok(())
+	}
+
+	/// Iterate the meshes that have already been parsed, in id order. Callers
+	/// that need every mesh (e.g. serialization) should `load_all` first.
+	pub(crate) fn iter(&self) -> impl Iterator<Item=&Mesh> {
+		self.cache.iter().filter_map(OnceCell::get)
+	}
 }
 
 #[derive(Debug, Clone)]
@@ -76,7 +129,7 @@ pub struct MeshAnimation {
 
 #[derive(Debug, Clone)]
 pub struct MeshAnimationChannel {
-	pub bone: String, // TODO: should be an index
+	pub bone: usize,
 	pub frames: Vec<MeshAnimationFrame>,
 }
 
@@ -90,41 +143,81 @@ pub struct MeshAnimationFrame {
 
 
 #[derive(Debug, Clone, Copy)]
-pub struct SceneRef<'toy> {
-	file: &'toy Project,
+pub struct SceneRef<'toy, 'data> {
+	file: &'toy Project<'data>,
 	scene: &'toy Scene,
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct EntityRef<'toy> {
-	file: &'toy Project,
+pub struct EntityRef<'toy, 'data> {
+	file: &'toy Project<'data>,
 	entity: &'toy Entity,
+	parent: Mat3x4,
 }
 
-impl Project {
-	pub fn find_scene(&self, name: &str) -> Option<SceneRef<'_>> {
+impl<'data> Project<'data> {
+	/// Parse a `.toy` buffer eagerly. A thin wrapper over [`Project::open`]
+	/// followed by [`Project::load_all`].
+	pub fn load(data: &'data [u8]) -> ToyResult<Project<'data>> {
+		let project = Project::open(data)?;
+		project.load_all()?;
+		Ok(project)
+	}
+
+	/// Index a `.toy` buffer in a single pass, recording each `MESH` section's
+	/// byte range without parsing it. Meshes are parsed lazily on first access
+	/// through [`Project::mesh`] or [`EntityRef::mesh`].
+	pub fn open(data: &'data [u8]) -> ToyResult<Project<'data>> {
+		crate::importer::open(data)
+	}
+
+	/// Parse (if necessary) and return the mesh at the given zero-based index,
+	/// caching the result for subsequent accesses. An entity's one-based
+	/// `mesh_id` maps to index `mesh_id - 1`.
+	pub fn mesh(&self, id: usize) -> ToyResult<&Mesh> {
+		self.meshes.mesh(id)
+	}
+
+	/// Whether the mesh at the given zero-based index has already been parsed
+	/// and cached.
+	pub fn is_loaded(&self, id: usize) -> bool {
+		self.meshes.is_loaded(id)
+	}
+
+	/// Force every mesh to be parsed up front, restoring the eager behaviour of
+	/// the old loader.
+	pub fn load_all(&self) -> ToyResult<()> {
+		self.meshes.load_all()
+	}
+
+	/// Number of `MESH` sections in the project.
+	pub fn mesh_count(&self) -> usize {
+		self.meshes.len()
+	}
+
+	pub fn find_scene(&self, name: &str) -> Option<SceneRef<'_, 'data>> {
 		self.scenes.iter()
 			.find(|e| e.name == name)
 			.map(|scene| SceneRef::from(self, scene))
 	}
 
-	pub fn find_entity(&self, name: &str) -> Option<EntityRef<'_>> {
+	pub fn find_entity(&self, name: &str) -> Option<EntityRef<'_, 'data>> {
 		self.entities.iter()
 			.find(|e| e.name == name)
 			.map(|entity| EntityRef::from(self, entity))
 	}
 
-	pub fn scenes(&self) -> impl Iterator<Item=SceneRef<'_>> {
+	pub fn scenes(&self) -> impl Iterator<Item=SceneRef<'_, 'data>> {
 		self.scenes.iter()
 			.map(move |entity| SceneRef::from(self, entity))
 	}
 
-	pub fn entities(&self) -> impl Iterator<Item=EntityRef<'_>> {
+	pub fn entities(&self) -> impl Iterator<Item=EntityRef<'_, 'data>> {
 		self.entities.iter()
 			.map(move |entity| EntityRef::from(self, entity))
 	}
 
-	pub fn entities_with_prefix<'t, 'p: 't>(&'t self, prefix: &'p str) -> impl Iterator<Item=EntityRef<'t>> {
+	pub fn entities_with_prefix<'t, 'p: 't>(&'t self, prefix: &'p str) -> impl Iterator<Item=EntityRef<'t, 'data>> {
 		self.entities()
 			.filter(move |entity| entity.name.starts_with(prefix))
 	}
@@ -140,14 +233,91 @@ impl Mesh {
 		self.uv_layers.iter()
 			.find(|l| l.name == name)
 	}
+
+	/// Evaluate `anim` at `time` (in seconds) into one skinning matrix per bone,
+	/// ready to deform a vertex as `sum_i weight_i * skin[index_i] * position`.
+	///
+	/// Each bone's animated local transform is composed with its inverse-bind
+	/// matrix; bones without a channel in `anim` fall back to their rest pose.
+	pub fn skinning_matrices(&self, anim: &MeshAnimation, time: f32) -> Vec<Mat3x4> {
+		let Some(data) = self.animation_data.as_ref() else {
+			return Vec::new()
+		};
+
+		let mut locals: Vec<Mat3x4> = data.bones.iter()
+			.map(MeshBone::rest_transform)
+			.collect();
+
+		for (channel, local) in anim.channels.iter().zip(anim.sample(time)) {
+			locals[channel.bone] = local;
+		}
+
+		data.bones.iter().zip(locals)
+			.map(|(bone, local)| local * bone.rest_transform().inverse())
+			.collect()
+	}
+}
+
+impl MeshBone {
+	/// Rest-pose transform of the bone: origin at `head`, oriented so the
+	/// bone's local +Y axis points along `tail - head`.
+	pub fn rest_transform(&self) -> Mat3x4 {
+		let direction = (self.tail - self.head).normalize();
+		Mat3x4::translate(self.head) * quat_from_y(direction).to_mat3x4()
+	}
+}
+
+impl MeshAnimation {
+	/// Sample every channel at `time` (in seconds), returning one interpolated
+	/// local transform per channel. `frame = time * fps` is split into the
+	/// surrounding keyframes `f0`/`f1` (clamped to the last frame) and blended
+	/// by `alpha`, lerping position/scale and slerping rotation.
+	pub fn sample(&self, time: f32) -> Vec<Mat3x4> {
+		let frame = time * self.fps;
+		let f0 = frame.floor();
+		let alpha = frame - f0;
+
+		self.channels.iter()
+			.map(|channel| {
+				if channel.frames.is_empty() {
+					return Mat3x4::identity()
+				}
+
+				let last = channel.frames.len() - 1;
+				let a = channel.frames[(f0 as usize).min(last)];
+				let b = channel.frames[(f0 as usize + 1).min(last)];
+
+				let position = a.position + (b.position - a.position) * alpha;
+				let scale = a.scale + (b.scale - a.scale) * alpha;
+				let rotation = a.rotation.slerp(b.rotation, alpha);
+
+				Mat3x4::translate(position) * rotation.to_mat3x4() * Mat3x4::scale(scale)
+			})
+			.collect()
+	}
+}
+
+/// Shortest-arc rotation taking the +Y axis onto the unit vector `to`.
+fn quat_from_y(to: Vec3) -> Quat {
+	let axis = Vec3::new(0.0, 1.0, 0.0).cross(to);
+	let w = 1.0 + to.y;
+
+	// `to` points straight down the -Y axis: any perpendicular axis gives a
+	// 180° rotation, pick +X.
+	if w <= 1.0e-6 {
+		return Quat::from_raw(1.0, 0.0, 0.0, 0.0)
+	}
+
+	let len = (axis.dot(axis) + w * w).sqrt();
+	Quat::from_raw(axis.x / len, axis.y / len, axis.z / len, w / len)
 }
 
-impl<'t> SceneRef<'t> {
-	pub fn from(file: &'t Project, scene: &'t Scene) -> SceneRef<'t> {
+impl<'t, 'data> SceneRef<'t, 'data> {
+	pub fn from(file: &'t Project<'data>, scene: &'t Scene) -> SceneRef<'t, 'data> {
 		SceneRef { file, scene }
 	}
 
-	pub fn entities(&self) -> impl Iterator<Item=EntityRef<'t>> {
+	pub fn entities(&self) -> impl Iterator<Item=EntityRef<'t, 'data>> {
 		let file = self.file;
 
 		self.scene.entities.iter()
@@ -155,24 +325,35 @@ impl<'t> SceneRef<'t> {
 			.map(move |entity| EntityRef::from(file, entity))
 	}
 
-	pub fn entities_with_prefix<'p: 't>(&self, prefix: &'p str) -> impl Iterator<Item=EntityRef<'t>> {
+	pub fn entities_with_prefix<'p: 't>(&self, prefix: &'p str) -> impl Iterator<Item=EntityRef<'t, 'data>> {
 		self.entities()
 			.filter(move |entity| entity.name.starts_with(prefix))
 	}
 
-	pub fn find_entity(&self, name: &str) -> Option<EntityRef<'t>> {
+	pub fn find_entity(&self, name: &str) -> Option<EntityRef<'t, 'data>> {
 		self.entities().find(|ent| ent.entity.name == name)
 	}
 }
 
-impl Deref for SceneRef<'_> {
+impl Deref for SceneRef<'_, '_> {
 	type Target = Scene;
 	fn deref(&self) -> &Self::Target { self.scene }
 }
 
-impl<'t> EntityRef<'t> {
-	pub fn from(file: &'t Project, entity: &'t Entity) -> EntityRef<'t> {
-		EntityRef { file, entity }
+impl<'t, 'data> EntityRef<'t, 'data> {
+	pub fn from(file: &'t Project<'data>, entity: &'t Entity) -> EntityRef<'t, 'data> {
+		EntityRef { file, entity, parent: Mat3x4::identity() }
+	}
+
+	/// Return a copy whose world transform is folded under `parent`.
+	pub fn with_parent(self, parent: Mat3x4) -> EntityRef<'t, 'data> {
+		EntityRef { parent: parent * self.parent, ..self }
+	}
+
+	/// The entity's transform, composed with any parent transform folded in by
+	/// [`EntityRef::with_parent`] or a [`Query`] (identity otherwise).
+	pub fn transform(&self) -> Mat3x4 {
+		self.parent * self.entity.transform()
 	}
 
 	pub fn mesh(&self) -> Option<&'t Mesh> {
@@ -182,7 +363,7 @@ impl<'t> EntityRef<'t> {
 			return None
 		}
 
-		self.file.meshes.get(mesh_id as usize - 1)
+		self.file.mesh(mesh_id as usize - 1).ok()
 	}
 }
 
@@ -194,43 +375,154 @@ impl Entity {
 	}
 }
 
-impl Deref for EntityRef<'_> {
+impl Deref for EntityRef<'_, '_> {
 	type Target = Entity;
 	fn deref(&self) -> &Self::Target { self.entity }
 }
 
-// TODO: entity queries
 // TODO: mesh building
 
 
-pub trait EntityCollection<'t> where Self: 't {
-	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t>>;
+pub trait EntityCollection<'t, 'data> where Self: 't {
+	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t, 'data>>;
 
-	fn into_entities_with_prefix<'p>(self, prefix: &'p str) -> impl Iterator<Item=EntityRef<'t>> + 'p
+	fn into_entities_with_prefix<'p>(self, prefix: &'p str) -> impl Iterator<Item=EntityRef<'t, 'data>> + 'p
 		where Self : Sized
 			, 't: 'p
 	{
 		self.into_entities()
 			.filter(move |entity| entity.name.starts_with(prefix))
 	}
+
+	/// Begin a composable [`Query`] over this collection.
+	fn query(self) -> Query<'t, 'data, impl Iterator<Item=EntityRef<'t, 'data>>>
+		where Self: Sized
+	{
+		Query::new(self.into_entities())
+	}
+}
+
+
+/// A lazily-composed filter over an [`EntityRef`] iterator. Filters chain by
+/// value and only run when the query is terminated with [`Query::iter`], so a
+/// `Query` works uniformly over a whole [`Project`], a [`SceneRef`], or any
+/// `EntityRef` iterator via [`EntityCollection`].
+pub struct Query<'t, 'data, I> {
+	entities: I,
+	filters: Vec<Box<dyn Fn(&EntityRef<'t, 'data>) -> bool + 't>>,
+	parent: Mat3x4,
+}
+
+impl<'t, 'data, I> Query<'t, 'data, I>
+	where I: Iterator<Item=EntityRef<'t, 'data>>
+{
+	fn new(entities: I) -> Query<'t, 'data, I> {
+		Query { entities, filters: Vec::new(), parent: Mat3x4::identity() }
+	}
+
+	fn with_filter(mut self, filter: impl Fn(&EntityRef<'t, 'data>) -> bool + 't) -> Query<'t, 'data, I> {
+		self.filters.push(Box::new(filter));
+		self
+	}
+
+	/// Keep only entities that reference a mesh.
+	pub fn with_mesh(self) -> Query<'t, 'data, I> {
+		self.with_filter(|entity| entity.mesh_id != 0)
+	}
+
+	/// Keep only entities that don't reference a mesh.
+	pub fn without_mesh(self) -> Query<'t, 'data, I> {
+		self.with_filter(|entity| entity.mesh_id == 0)
+	}
+
+	/// Keep entities whose name matches `pattern`, where `*` is a wildcard
+	/// (so `"spawn*"` is a prefix match, `"*light"` a suffix match).
+	pub fn name_matches(self, pattern: &'t str) -> Query<'t, 'data, I> {
+		self.with_filter(move |entity| glob_match(pattern, &entity.name))
+	}
+
+	/// Keep entities whose position lies within `radius` of `center`.
+	pub fn in_sphere(self, center: Vec3, radius: f32) -> Query<'t, 'data, I> {
+		self.with_filter(move |entity| {
+			let offset = entity.position - center;
+			offset.dot(offset) <= radius * radius
+		})
+	}
+
+	/// Keep entities whose position lies within the axis-aligned box `min..max`.
+	pub fn in_aabb(self, min: Vec3, max: Vec3) -> Query<'t, 'data, I> {
+		self.with_filter(move |entity| {
+			let p = entity.position;
+			p.x >= min.x && p.x <= max.x
+				&& p.y >= min.y && p.y <= max.y
+				&& p.z >= min.z && p.z <= max.z
+		})
+	}
+
+	/// Fold a scene-root transform into the transform of every returned entity.
+	pub fn transformed_by(mut self, parent: Mat3x4) -> Query<'t, 'data, I> {
+		self.parent = parent * self.parent;
+		self
+	}
+
+	/// Terminate the query, yielding the entities that pass every filter.
+	pub fn iter(self) -> impl Iterator<Item=EntityRef<'t, 'data>> {
+		let Query { entities, filters, parent } = self;
+
+		entities
+			.map(move |entity| entity.with_parent(parent))
+			.filter(move |entity| filters.iter().all(|filter| filter(entity)))
+	}
+}
+
+
+/// Match `text` against a `*`-wildcard glob `pattern`. A pattern with no `*` is
+/// an exact match; otherwise each `*` matches any run of characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+	if !pattern.contains('*') {
+		return pattern == text
+	}
+
+	let segments: Vec<&str> = pattern.split('*').collect();
+
+	// The leading segment (before the first `*`) must be a prefix.
+	let Some(rest) = text.strip_prefix(segments[0]) else {
+		return false
+	};
+	let mut rest = rest;
+
+	let last = segments.len() - 1;
+	for (i, &segment) in segments.iter().enumerate().skip(1) {
+		if i == last {
+			// The trailing segment (after the last `*`) must be a suffix.
+			return rest.ends_with(segment)
+		}
+
+		match rest.find(segment) {
+			Some(at) => rest = &rest[at + segment.len()..],
+			None => return false,
+		}
+	}
+
+	true
 }
 
-impl<'t> EntityCollection<'t> for &'t Project {
-	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t>> {
+impl<'t, 'data> EntityCollection<'t, 'data> for &'t Project<'data> {
+	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t, 'data>> {
 		self.entities()
 	}
 }
 
-impl<'t> EntityCollection<'t> for SceneRef<'t> {
-	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t>> {
+impl<'t, 'data> EntityCollection<'t, 'data> for SceneRef<'t, 'data> {
+	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t, 'data>> {
 		self.entities()
 	}
 }
 
-impl<'t, T> EntityCollection<'t> for T
-	where T: Iterator<Item=EntityRef<'t>> + 't
+impl<'t, 'data, T> EntityCollection<'t, 'data> for T
+	where T: Iterator<Item=EntityRef<'t, 'data>> + 't
 {
-	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t>> {
+	fn into_entities(self) -> impl Iterator<Item=EntityRef<'t, 'data>> {
 		self
 	}
 }
\ No newline at end of file